@@ -0,0 +1,13 @@
+// Common surface of chat_context::ChatContext and message::Context so callers can swap implementations
+#[allow(async_fn_in_trait)]
+pub trait Conversation {
+    type Message;
+
+    async fn add(&mut self, message: Self::Message) -> anyhow::Result<()>;
+
+    // Generates a reply to the current history and appends it. Ok(None) means the assistant chose not to reply
+    async fn respond(&mut self) -> anyhow::Result<Option<Self::Message>>;
+
+    // Token count of the prompt that would currently be sent
+    fn tokens(&self) -> usize;
+}
@@ -4,12 +4,17 @@ use ansi_term::Colour::{White, Red, Green, Blue};
 
 use chat_context::{MetaChatMessage, MessageType};
 use openai_rs::{chat::{ChatHistoryBuilder, ChatMessage, Role}, context::Context};
-use tiktoken::{CoreBPE, model::{cl100k_base, model_cl100k_base}};
+use tiktoken::CoreBPE;
 
-use crate::chat_context::{ChatContext, UserAlias};
+use crate::chat_context::{default_params, ChatContext, UserAlias, SystemSlot};
+use crate::model::{get_max_tokens, get_tokens_per_message, role_str};
 
 mod chat_context;
+mod conversation;
 mod message;
+mod model;
+mod rate_limiter;
+mod response_cache;
 
 const AI_MODEL: &str = "gpt-4";
 
@@ -29,9 +34,9 @@ async fn main() {
     println!("Initializing context...");
     let mut chat_context = ChatContext::new(AI_MODEL.to_string(), get_api_key().expect("Couldn't get API key")).await.unwrap();
 
-    chat_context.get_history().push(MetaChatMessage { chat_message: ChatMessage::new(Role::System, "This is a group-chat with multiple users. Your responses are concise and truthful", Some("context".to_string())), message_type: MessageType::AssistantMessage });
-    chat_context.get_history().push(MetaChatMessage { chat_message: ChatMessage::new(Role::System, "Always use the first listed name when referring to users.\nu0: \"James\", \"Jimmy\", \"Hazel\"\nu1: \"Donna\", \"Delphine\"\nu2: [[unknown]]", Some("aliases".to_string())), message_type: MessageType::AssistantMessage });
-    chat_context.get_history().push(MetaChatMessage { chat_message: ChatMessage::new(Role::System, "You are Jarvis. You only respond when the most recent message is for Jarvis, otherwise you send an empty message", None), message_type: MessageType::AssistantMessage });
+    chat_context.set_system_message(SystemSlot::Context, "This is a group-chat with multiple users. Your responses are concise and truthful".to_string());
+    chat_context.set_system_message(SystemSlot::Aliases, "Always use the first listed name when referring to users.\nu0: \"James\", \"Jimmy\", \"Hazel\"\nu1: \"Donna\", \"Delphine\"\nu2: [[unknown]]".to_string());
+    chat_context.set_system_message(SystemSlot::Persona, "You are Jarvis. You only respond when the most recent message is for Jarvis, otherwise you send an empty message".to_string());
 
     loop {
         print!("{} {}", Red.paint("You:"), Blue.prefix().to_string());
@@ -42,13 +47,18 @@ async fn main() {
             continue;
         }
 
-        let completion = chat_context.send_message(user_message.unwrap()).await;
-    
-        if completion.chat_message.content.len() > 0 {
-            println!("{} {}", Red.paint("Assistant:"), Green.paint(&completion.chat_message.content));
-    
-            chat_context.get_history().push(completion);
-        }
+        let completion = match chat_context.send_message(user_message.unwrap()).await {
+            Ok(Some(completion)) => completion,
+            Ok(None) => continue,
+            Err(err) => {
+                println!("{} {}", Red.paint("Error:"), err);
+                continue;
+            }
+        };
+
+        println!("{} {}", Red.paint("Assistant:"), Green.paint(&completion.chat_message.content));
+
+        chat_context.push_message(completion);
     }
 }
 
@@ -68,7 +78,7 @@ fn accept_user_message() -> Option<MetaChatMessage> {
         _ => ("u2".to_string(), input)
     };
 
-    return Some(MetaChatMessage { chat_message: ChatMessage::new(Role::User, input, Some(name)), message_type: MessageType::UserMessage { sender: UserAlias { id: 4, } }});
+    return Some(MetaChatMessage::new(ChatMessage::new(Role::User, input, Some(name)), MessageType::UserMessage { sender: UserAlias::new(4, Vec::new()) }));
 }
 
 fn get_api_key() -> anyhow::Result<String> {
@@ -85,61 +95,20 @@ fn get_api() -> anyhow::Result<Context> {
     ))
 }
 
-async fn get_model(model: &str) -> Option<CoreBPE> {
-    return match model {
-        "gpt-4" | "gpt-3.5-turbo" | "text-embedding-ada-002" => {
-            let model = model_cl100k_base().await;
-            assert!(model.is_ok(), "Could not download model (model_cl100k_base): {:?}", model);
-
-            let model = cl100k_base(model.unwrap());
-            assert!(model.is_ok(), "Could not load model (cl100k_base): {:?}", model.err().unwrap());
-            
-            return Some(model.unwrap());
-        }
-        _ => None
-    }
-}
-
-fn get_tokens_per_message(model: &str) -> Option<usize> {
-    match model {
-        "gpt-4" => Some(3),
-        "gpt-3.5-turbo" => Some(4),
-        _ => None
-    }
-}
-
-fn role_str(role: &Role) -> &str {
-    match role {
-        Role::Assistant => "Assistant",
-        Role::System => "System",
-        Role::User => "User",
-    }
-}
-
 fn count_tokens(history: &Vec<ChatMessage>, encoding: &CoreBPE, model: &str) -> usize {
     let mut count = 0;
-    let tpm = get_tokens_per_message(model).expect("Unknown tokens-per-message value");
+    let tpm = get_tokens_per_message(model) as usize;
     for entry in history {
         count += tpm + encoding.encode_ordinary(&entry.content).len() + encoding.encode_ordinary(role_str(&entry.role)).len();
     }
     return count;
 }
 
-fn get_max_tokens(model: &str) -> Option<usize> {
-    match model {
-        "gpt-4" => Some(8192),
-        "gpt-4-32k" => Some(32768),
-        "gpt-3.5-turbo" => Some(4096),
-        "code-davinci-002" => Some(8001),
-        _ => None
-    }
-}
-
 async fn generate_completion(ctx: &Context, history: &Vec<ChatMessage>, model: &str, encoding: &CoreBPE, token_limit: Option<NonZeroUsize>) -> ChatMessage {
     let message_token_count = count_tokens(history, encoding, model);
-    let abs_max = get_max_tokens(model).expect("Undefined maximum token count for model!");
+    let abs_max = get_max_tokens(model).expect("Undefined maximum token count for model!") as usize;
 
-    if message_token_count >= abs_max - get_tokens_per_message(model).unwrap() {
+    if message_token_count >= abs_max - get_tokens_per_message(model) as usize {
         panic!("Message history exceeds token limit! No new message can be generated.");
     }
 
@@ -152,9 +121,7 @@ async fn generate_completion(ctx: &Context, history: &Vec<ChatMessage>, model: &
     
     let completion = ctx
         .create_chat_completion_sync(
-            ChatHistoryBuilder::default()
-                .temperature(0.55) // Model suffers from excessive hallucination. TODO: fine-tune temperature
-                .frequency_penalty(0.1)
+            default_params(model).apply(ChatHistoryBuilder::default())
                 .messages(history.clone())
                 .model(model),
         )
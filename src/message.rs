@@ -1,23 +1,29 @@
-use std::{ptr::NonNull, marker::PhantomPinned, pin::Pin, cmp::min, num::NonZeroUsize, fmt::Display, error::Error};
+use std::{collections::HashMap, ptr::NonNull, marker::PhantomPinned, pin::Pin, cmp::min, num::NonZeroUsize, fmt::Display, error::Error};
 
 use openai_rs::{chat::{ChatMessage, Role, ChatHistoryBuilder}, context::Context as OpenAIContext};
-use tiktoken::{CoreBPE, model::{model_cl100k_base, cl100k_base}};
+use tiktoken::CoreBPE;
+
+use crate::{chat_context::{default_params, format_alias_line, api_name, levenshtein, AssistantNamePolicy, ChatContext, CompletionBackend, ContextParts, MessageType, MetaChatMessage, SystemSlot, UserAlias, DEFAULT_UNKNOWN_USER_PLACEHOLDER, REPLY_PRIMING_TOKENS}, conversation::Conversation, model::{get_model, get_max_tokens, get_tokens_per_message, count_message_tokens}};
 
 const PROMPT_COMPRESS: &str = "Summarize the chat history precisely and concisely";
+const PROMPT_COMPRESS_FACTS: &str = "Summarize the chat history as a list of concrete facts (names, dates, decisions), one per line, formatted as \"- key: value\"";
+const PROMPT_SHRINK_SUMMARY: &str = "Condense this summary without losing names, dates or decisions. Use at most";
+const PROMPT_ALIASES: &str = "Always use the first listed name when referring to users.";
 
 type UserAliases = Vec<String>;
 
 #[derive(Debug)]
-struct ContextOverrunError {
+pub struct ContextOverrunError {
     max_tokens: usize,
     context_budget: usize,
     history_budget: usize,
-    alias_budget: usize
+    alias_budget: usize,
+    response_reserve: usize
 }
 
 impl Display for ContextOverrunError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&format!("Context budget overrun. Context ({}), history ({}) and alias ({}) budgets must be at most {} tokens", self.context_budget, self.history_budget, self.alias_budget, self.max_tokens))?;
+        f.write_str(&format!("Context budget overrun. Context ({}), history ({}), alias ({}) and response ({}) budgets must be at most {} tokens", self.context_budget, self.history_budget, self.alias_budget, self.response_reserve, self.max_tokens))?;
         Ok(())
     }
 }
@@ -25,12 +31,13 @@ impl Display for ContextOverrunError {
 impl Error for ContextOverrunError {}
 
 impl ContextOverrunError {
-    fn new(max_tokens: usize, context_budget: usize, history_budget: usize, alias_budget: usize) -> Self {
+    fn new(max_tokens: usize, context_budget: usize, history_budget: usize, alias_budget: usize, response_reserve: usize) -> Self {
         Self {
             max_tokens,
             context_budget,
             history_budget,
-            alias_budget
+            alias_budget,
+            response_reserve
         }
     }
 }
@@ -63,6 +70,77 @@ impl Display for InvalidModelTokenInformation {
 
 impl Error for InvalidModelTokenInformation {}
 
+#[derive(Debug)]
+pub struct BudgetExceededError {
+    section: &'static str,
+    tokens: usize,
+    budget: usize
+}
+
+impl Display for BudgetExceededError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("Initial {} uses {} tokens, exceeding its budget of {} tokens", self.section, self.tokens, self.budget))?;
+        Ok(())
+    }
+}
+
+impl Error for BudgetExceededError {}
+
+#[derive(Debug)]
+pub struct UnconvertibleStateError {
+    state: &'static str
+}
+
+impl Display for UnconvertibleStateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("Chat context has {} that can't be carried over", self.state))?;
+        Ok(())
+    }
+}
+
+impl Error for UnconvertibleStateError {}
+
+#[derive(Debug)]
+struct ArchiveDisabledError;
+
+impl Display for ArchiveDisabledError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Message archiving is disabled; enable archive_compressed to retain compressed history")?;
+        Ok(())
+    }
+}
+
+impl Error for ArchiveDisabledError {}
+
+#[derive(Debug)]
+pub struct UserLimitError {
+    max_users: usize
+}
+
+impl Display for UserLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("Cannot register another user: context is limited to {} users", self.max_users))?;
+        Ok(())
+    }
+}
+
+impl Error for UserLimitError {}
+
+#[derive(Debug)]
+pub struct HistoryOverflowError {
+    tokens: usize,
+    limit: usize
+}
+
+impl Display for HistoryOverflowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("History would use {} tokens, exceeding the limit of {} tokens with automatic compression disabled", self.tokens, self.limit))?;
+        Ok(())
+    }
+}
+
+impl Error for HistoryOverflowError {}
+
 #[derive(Debug)]
 enum ContextCreationError {
     ContextOverrunError(ContextOverrunError),
@@ -100,11 +178,78 @@ impl From<InvalidModelTokenInformation> for ContextCreationError {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryPlacement {
+    Front,
+    AfterSystem,
+    Custom(usize),
+}
+
+impl Default for SummaryPlacement {
+    fn default() -> Self {
+        SummaryPlacement::Front
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryInstructionPlacement {
+    Before,
+    After,
+}
+
+impl Default for SummaryInstructionPlacement {
+    fn default() -> Self {
+        SummaryInstructionPlacement::After
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryFormat {
+    Prose,
+    Facts,
+}
+
+impl Default for SummaryFormat {
+    fn default() -> Self {
+        SummaryFormat::Prose
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine {
+    Kept(String),
+    Added(String),
+    Removed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionPlan {
+    pub dropped_indices: Vec<usize>,
+    pub retained: usize,
+}
+
+// history_limit is what's left of max_tokens after the other budgets; compression fires once
+// history_tokens reaches it, so slack is how much more history fits before that
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetReport {
+    pub max_tokens: usize,
+    pub summary_budget: usize,
+    pub summary_instruction_budget: usize,
+    pub history_target: usize,
+    pub alias_budget: usize,
+    pub response_reserve: usize,
+    pub history_limit: usize,
+    pub history_tokens: usize,
+    pub summary_tokens: usize,
+    pub slack: usize,
+}
+
 pub struct UserList {
     pub users: Vec<UserAliases>,
     _pin: PhantomPinned
 }
 
+#[derive(Clone)]
 pub enum User {
     Assistant,
     System,
@@ -113,29 +258,35 @@ pub enum User {
     }
 }
 
+#[derive(Clone)]
 pub struct Message {
     pub sender: User,
-    pub message: String
+    pub message: String,
+    // Index of the sender in the user list, fixed when the message is added so its uN label never changes
+    user_index: Option<usize>
 }
 
 impl Message {
     fn new(sender: User, message: String) -> Self {
         Self {
             sender,
-            message
+            message,
+            user_index: None
         }
     }
     
-    fn to_chat_message(&self, user_index: Option<usize>) -> ChatMessage {
+    fn to_chat_message(&self, assistant_name: Option<&str>) -> ChatMessage {
         ChatMessage::new(
             match self.sender {
                 User::System => Role::System,
                 User::Assistant => Role::Assistant,
                 User::User { aliases } => Role::User
             },
-            self.message,
-            if let Some(user_index) = user_index {
+            self.message.clone(),
+            if let Some(user_index) = self.user_index {
                 Some(format!("u{user_index}"))
+            } else if let User::Assistant = self.sender {
+                assistant_name.map(api_name)
             } else {
                 None
             }
@@ -155,6 +306,20 @@ pub struct Context {
     summary_instruction_budget: usize,
     history_target: usize,
     alias_budget: usize,
+    summary_placement: SummaryPlacement,
+    archive: Vec<Message>,
+    archive_compressed: bool,
+    summary_instruction_placement: SummaryInstructionPlacement,
+    assistant_name: Option<String>,
+    assistant_name_policy: AssistantNamePolicy,
+    summary_format: SummaryFormat,
+    facts: Vec<(String, String)>,
+    max_users: Option<usize>,
+    previous_summary: Option<String>,
+    compression_hysteresis: usize,
+    auto_compress: bool,
+    response_reserve: usize,
+    completion_backend: Option<CompletionBackend>,
 }
 
 impl UserList {
@@ -165,17 +330,23 @@ impl UserList {
         })
     }
 
-    fn add_user(&mut self) {
-        self.users.push(Vec::new());
+    // The list itself stays put; only the alias vectors it owns are handed out mutably
+    fn users_mut(self: Pin<&mut Self>) -> &mut Vec<UserAliases> {
+        unsafe { &mut self.get_unchecked_mut().users }
     }
 
-    fn add_existing_user(&mut self, aliases: UserAliases) {
-        self.users.push(aliases);
+    fn add_user(self: Pin<&mut Self>) {
+        self.users_mut().push(Vec::new());
+    }
+
+    fn add_existing_user(self: Pin<&mut Self>, aliases: UserAliases) {
+        self.users_mut().push(aliases);
     }
 }
 
 impl Context {
-    async fn new_from_api(model: String, openai_api_key: String, summary_budget: NonZeroUsize, history_target: NonZeroUsize, alias_budget: NonZeroUsize) -> Result<Self, ContextCreationError> {
+    // response_reserve is kept free of history so there is always room for a reply of at least that many tokens
+    async fn new_from_api(model: String, openai_api_key: String, summary_budget: NonZeroUsize, history_target: NonZeroUsize, alias_budget: NonZeroUsize, response_reserve: usize) -> Result<Self, ContextCreationError> {
         let encoding = get_model(&model).await.ok_or(MissingModelError { model: model.clone() })?;
         Ok(Context::new(
             NonZeroUsize::new(get_max_tokens(&model).ok_or(MissingModelError { model: model.clone() })? as usize).ok_or(InvalidModelTokenInformation { model: model.clone() })?,
@@ -184,15 +355,15 @@ impl Context {
             OpenAIContext::new(openai_api_key),
             summary_budget,
             history_target,
-            alias_budget
+            alias_budget,
+            response_reserve
         )?)
     }
 
-    fn new(max_tokens: NonZeroUsize, model: String, encoding: CoreBPE, openai_context: OpenAIContext, summary_budget: NonZeroUsize, history_target: NonZeroUsize, alias_budget: NonZeroUsize) -> Result<Self, ContextOverrunError> {
-        let summary_instruction_budget = count_message_tokens(&get_summary_instruction(), &encoding, &model) as usize;
-        let summary_budget = summary_budget.get() + count_message_tokens(&get_summary_message(None), &encoding, &model) as usize;
-        if history_target.get() + summary_budget + alias_budget.get() + summary_instruction_budget >= max_tokens.get() {
-            Err(ContextOverrunError::new(max_tokens.get(), history_target.get(), summary_budget, alias_budget.get()))
+    fn new(max_tokens: NonZeroUsize, model: String, encoding: CoreBPE, openai_context: OpenAIContext, summary_budget: NonZeroUsize, history_target: NonZeroUsize, alias_budget: NonZeroUsize, response_reserve: usize) -> Result<Self, ContextOverrunError> {
+        let (summary_budget, summary_instruction_budget) = summary_budgets(summary_budget.get(), &encoding, &model);
+        if let Err(err) = check_budgets(max_tokens.get(), summary_budget, summary_instruction_budget, history_target.get(), alias_budget.get(), response_reserve) {
+            Err(err)
         } else {
             Ok(Self {
                 users: UserList::new(),
@@ -205,78 +376,411 @@ impl Context {
                 summary_budget: summary_budget,
                 summary_instruction_budget,
                 history_target: history_target.get(),
-                alias_budget: alias_budget.get()
+                alias_budget: alias_budget.get(),
+                summary_placement: SummaryPlacement::default(),
+                archive: Vec::new(),
+                archive_compressed: false,
+                summary_instruction_placement: SummaryInstructionPlacement::default(),
+                assistant_name: None,
+                assistant_name_policy: AssistantNamePolicy::default(),
+                summary_format: SummaryFormat::default(),
+                facts: Vec::new(),
+                max_users: None,
+                previous_summary: None,
+                compression_hysteresis: 0,
+                auto_compress: true,
+                response_reserve,
+                completion_backend: None
             })
         }
     }
 
+    // For restoring a session: starts with an existing summary instead of waiting for the first compression
+    pub fn with_initial_summary(mut self, summary: String) -> Result<Self, BudgetExceededError> {
+        let tokens = count_message_tokens(&get_summary_message(Some(summary.clone())), &self.encoding, &self.model) as usize;
+        if tokens > self.summary_budget {
+            return Err(BudgetExceededError { section: "summary", tokens, budget: self.summary_budget });
+        }
+
+        self.summary = Some(summary);
+        Ok(self)
+    }
+
+    pub fn with_initial_messages(mut self, mut messages: Vec<Message>) -> Result<Self, BudgetExceededError> {
+        let mut tokens = 0;
+        for message in messages.iter_mut() {
+            if message.user_index.is_none() {
+                message.user_index = self.update_user_list(&message.sender).unwrap_or_else(|err| {
+                    eprintln!("{err}");
+                    None
+                });
+            }
+            tokens += count_message_tokens(&message.to_chat_message(self.assistant_label()), &self.encoding, &self.model) as usize;
+        }
+
+        let budget = self.history_token_limit();
+        if tokens >= budget {
+            return Err(BudgetExceededError { section: "history", tokens, budget });
+        }
+
+        self.messages = messages;
+        Ok(self)
+    }
+
+    // Users are ordered by alias id, so uN labels carry over when the ids are contiguous from 0. The Context slot
+    // becomes the summary. Everything is checked before the ChatContext is taken apart, so a failed conversion hands
+    // it back unchanged
+    pub fn from_chat_context(chat_context: ChatContext, summary_budget: NonZeroUsize, history_target: NonZeroUsize, alias_budget: NonZeroUsize, response_reserve: usize) -> Result<Self, (ChatContext, anyhow::Error)> {
+        let (users, messages) = Self::convert_history(&chat_context);
+        if let Err(err) = Self::check_conversion(&chat_context, &messages, summary_budget, history_target, alias_budget, response_reserve) {
+            return Err((chat_context, err));
+        }
+
+        let parts = chat_context.into_parts();
+        let max_tokens = NonZeroUsize::new(parts.max_tokens as usize).expect("Max tokens are checked before conversion");
+        let mut context = Context::new(max_tokens, parts.model, parts.encoding, parts.api_context, summary_budget, history_target, alias_budget, response_reserve)
+            .expect("Budgets are checked before conversion");
+        context.users = users;
+        if let Some(name) = parts.assistant_name {
+            context.set_assistant_name(name);
+        }
+        context.messages = messages;
+        context.summary = parts.summary;
+        Ok(context)
+    }
+
+    // Senders are mapped by alias id, never by name, so users sharing a name (or having none) stay apart. The
+    // messages point into the returned user list
+    fn convert_history(chat_context: &ChatContext) -> (Pin<Box<UserList>>, Vec<Message>) {
+        let history = chat_context.get_history();
+        let senders = history.iter().filter_map(|message| match message.message_type {
+            MessageType::UserMessage { ref sender } => Some(sender),
+            MessageType::AssistantMessage => None
+        });
+        let mut aliases = Vec::<&UserAlias>::new();
+        for alias in chat_context.user_aliases().iter().chain(senders) {
+            if !aliases.iter().any(|known| known.id() == alias.id()) {
+                aliases.push(alias);
+            }
+        }
+        aliases.sort_by_key(|alias| alias.id());
+
+        let user_index = aliases.iter()
+            .enumerate()
+            .map(|(index, alias)| (alias.id(), index))
+            .collect::<HashMap<u16, usize>>();
+        let mut users = aliases.iter().map(|alias| alias.names().to_vec()).collect::<Vec<UserAliases>>();
+
+        // User messages without a sender share one anonymous user
+        let anonymous = if history.iter().any(|message| matches!((&message.chat_message.role, &message.message_type), (Role::User, MessageType::AssistantMessage))) {
+            users.push(Vec::new());
+            Some(users.len() - 1)
+        } else {
+            None
+        };
+        let users = Box::pin(UserList { users, _pin: PhantomPinned });
+
+        let messages = history.iter().map(|message| {
+            let index = match (&message.chat_message.role, &message.message_type) {
+                (Role::User, MessageType::UserMessage { sender }) => Some(user_index[&sender.id()]),
+                (Role::User, MessageType::AssistantMessage) => anonymous,
+                _ => None
+            };
+            let sender = match (&message.chat_message.role, index) {
+                (Role::System, _) => User::System,
+                (Role::Assistant, _) => User::Assistant,
+                (Role::User, index) => User::User { aliases: NonNull::from(&users.users[index.unwrap()]) }
+            };
+            let mut converted = Message::new(sender, message.chat_message.content.clone());
+            converted.user_index = index;
+            converted
+        }).collect::<Vec<Message>>();
+
+        return (users, messages);
+    }
+
+    // The checks Context::new, with_initial_messages and with_initial_summary would make, done by reference
+    fn check_conversion(chat_context: &ChatContext, messages: &[Message], summary_budget: NonZeroUsize, history_target: NonZeroUsize, alias_budget: NonZeroUsize, response_reserve: usize) -> anyhow::Result<()> {
+        if let Some(state) = chat_context.unconvertible_state() {
+            return Err(UnconvertibleStateError { state }.into());
+        }
+
+        let (encoding, model) = (chat_context.encoding(), chat_context.model());
+        let max_tokens = NonZeroUsize::new(chat_context.max_tokens() as usize).ok_or(InvalidModelTokenInformation { model: model.to_string() })?.get();
+        let (summary_budget, summary_instruction_budget) = summary_budgets(summary_budget.get(), encoding, model);
+        check_budgets(max_tokens, summary_budget, summary_instruction_budget, history_target.get(), alias_budget.get(), response_reserve)?;
+
+        // The converted context keeps the default Persona policy, so replies are labelled with the assistant name
+        let tokens = messages.iter()
+            .map(|message| count_message_tokens(&message.to_chat_message(chat_context.get_assistant_name()), encoding, model) as usize)
+            .sum::<usize>();
+        let budget = max_tokens - alias_budget.get() - summary_budget - summary_instruction_budget - response_reserve;
+        if tokens >= budget {
+            return Err(BudgetExceededError { section: "history", tokens, budget }.into());
+        }
+
+        if let Some(summary) = chat_context.get_system_message(SystemSlot::Context) {
+            let tokens = count_message_tokens(&get_summary_message(Some(summary.to_string())), encoding, model) as usize;
+            if tokens > summary_budget {
+                return Err(BudgetExceededError { section: "summary", tokens, budget: summary_budget }.into());
+            }
+        }
+        return Ok(());
+    }
+
+    // The summary (with any facts) becomes the Context slot. Archived messages are not carried over
+    pub fn into_chat_context(self) -> ChatContext {
+        let user_aliases = self.users.users.iter()
+            .enumerate()
+            .map(|(index, names)| UserAlias::new(index as u16, names.clone()))
+            .collect::<Vec<UserAlias>>();
+
+        let history = self.messages.iter().map(|message| {
+            let message_type = match message.user_index {
+                Some(index) => MessageType::UserMessage { sender: user_aliases[index].clone() },
+                None => MessageType::AssistantMessage
+            };
+            MetaChatMessage::new(message.to_chat_message(self.assistant_label()), message_type)
+        }).collect::<Vec<MetaChatMessage>>();
+        let summary = self.summary_text();
+
+        ChatContext::from_parts(ContextParts {
+            model: self.model,
+            encoding: self.encoding,
+            max_tokens: self.max_tokens as i64,
+            api_context: self.openai_context,
+            history,
+            user_aliases,
+            summary,
+            assistant_name: self.assistant_name
+        })
+    }
+
+    pub fn validate_budgets(&self) -> Result<(), ContextOverrunError> {
+        check_budgets(self.max_tokens, self.summary_budget, self.summary_instruction_budget, self.history_target, self.alias_budget, self.response_reserve)
+    }
+
+    pub fn budget_report(&self) -> BudgetReport {
+        let history_limit = self.history_token_limit();
+        let history_tokens = self.count_message_tokens() as usize;
+        BudgetReport {
+            max_tokens: self.max_tokens,
+            summary_budget: self.summary_budget,
+            summary_instruction_budget: self.summary_instruction_budget,
+            history_target: self.history_target,
+            alias_budget: self.alias_budget,
+            response_reserve: self.response_reserve,
+            history_limit,
+            history_tokens,
+            summary_tokens: self.tokens() - history_tokens,
+            slack: history_limit.saturating_sub(history_tokens)
+        }
+    }
+
     fn find_user_by_alias(&self, find: NonNull<UserAliases>) -> Option<usize> {
+        let find = unsafe { find.as_ref() };
         for (index, user) in self.users.users.iter().enumerate() {
-            if unsafe { find.as_ref() == user } {
+            if find.len() == user.len() && find.iter().zip(user.iter()).all(|(a, b)| normalize_name(a) == normalize_name(b)) {
                 return Some(index);
             }
         }
         return None;
     }
 
-    fn find_user(&self, find: &User) -> Option<usize> {
-        if let User::User { aliases } = find {
-            self.find_user_by_alias(*aliases)
-        } else {
-            None
+    // Maps a name to a user, tolerating casing, surrounding whitespace and small typos
+    pub fn resolve_fuzzy(&self, name: &str) -> Option<usize> {
+        let name = normalize_name(name);
+        let max_distance = (name.chars().count() / 4).max(1);
+        let mut best: Option<(usize, usize)> = None;
+
+        for (index, user) in self.users.users.iter().enumerate() {
+            for alias in user {
+                let distance = levenshtein(&name, &normalize_name(alias));
+                if distance == 0 {
+                    return Some(index);
+                }
+
+                if distance <= max_distance && best.map_or(true, |(_, best_distance)| distance < best_distance) {
+                    best = Some((index, distance));
+                }
+            }
         }
+
+        return best.map(|(index, _)| index);
     }
 
-    fn update_user_list(&mut self, user: &User) -> Option<usize> {
+    fn update_user_list(&mut self, user: &User) -> Result<Option<usize>, UserLimitError> {
         if let User::User { aliases } = user {
-            Some(if let Some(index) = self.find_user_by_alias(*aliases) {
-                index
-            } else {
-                eprintln!("Attempt to add unregistered user to history! This is probably a bug.");
+            if let Some(index) = self.find_user_by_alias(*aliases) {
+                return Ok(Some(index));
+            }
 
-                let copy = unsafe { (*aliases.as_ptr()).clone() };
-                self.users.add_existing_user(copy);
-                self.users.users.len() - 1
-            })
+            if let Some(max_users) = self.max_users {
+                if self.users.users.len() >= max_users {
+                    return Err(UserLimitError { max_users });
+                }
+            }
+
+            eprintln!("Attempt to add unregistered user to history! This is probably a bug.");
+
+            let copy = unsafe { (*aliases.as_ptr()).clone() };
+            self.users.as_mut().add_existing_user(copy);
+            Ok(Some(self.users.users.len() - 1))
         } else {
-            None
+            Ok(None)
         }
     }
 
+    pub fn set_max_users(&mut self, max_users: Option<usize>) {
+        self.max_users = max_users;
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.users.users.len()
+    }
+
     fn history_token_limit(&self) -> usize {
-        self.max_tokens - self.alias_budget - self.summary_budget - self.summary_instruction_budget
+        self.max_tokens - self.alias_budget - self.summary_budget - self.summary_instruction_budget - self.response_reserve
+    }
+
+    pub fn set_assistant_name(&mut self, name: String) {
+        self.assistant_name = Some(name);
+    }
+
+    pub fn set_assistant_name_policy(&mut self, assistant_name_policy: AssistantNamePolicy) {
+        self.assistant_name_policy = assistant_name_policy;
+    }
+
+    fn assistant_label(&self) -> Option<&str> {
+        match self.assistant_name_policy {
+            AssistantNamePolicy::Omit => None,
+            AssistantNamePolicy::Persona => self.assistant_name.as_deref()
+        }
+    }
+
+    // Answers every request this context makes (replies and summaries) instead of the API
+    pub fn set_completion_backend(&mut self, completion_backend: CompletionBackend) {
+        self.completion_backend = Some(completion_backend);
+    }
+
+    async fn complete(&self, model: &str, messages: Vec<ChatMessage>, max_tokens: usize) -> anyhow::Result<String> {
+        if let Some(ref backend) = self.completion_backend {
+            return Ok(backend(&messages, max_tokens)?.content);
+        }
+
+        Ok(self.openai_context.create_chat_completion_sync(
+            default_params(model).apply(ChatHistoryBuilder::default())
+                .max_tokens(max_tokens as u64)
+                .model(model.to_string())
+                .messages(messages)
+        ).await?.choices.remove(0).message.content)
+    }
+
+    pub fn set_summary_placement(&mut self, summary_placement: SummaryPlacement) {
+        self.summary_placement = summary_placement;
     }
 
-    pub async fn add_message(&mut self, message: String, user: User) {
-        let user_index = self.update_user_list(&user);
+    // Fails instead of compressing when automatic compression is disabled and the message doesn't fit
+    pub async fn add_message(&mut self, message: String, user: User) -> anyhow::Result<()> {
+        // Over the user cap the message is still kept, just without a user label
+        let user_index = self.update_user_list(&user).unwrap_or_else(|err| {
+            eprintln!("{err}");
+            None
+        });
         let total_tokens = self.count_message_tokens();
-        let message = Message::new(user, message);
+        let mut message = Message::new(user, message);
+        message.user_index = user_index;
 
-        let message_tokens = count_message_tokens(&message.to_chat_message(user_index), &self.encoding, &self.model);
+        let message_tokens = count_message_tokens(&message.to_chat_message(self.assistant_label()), &self.encoding, &self.model);
 
-        if (total_tokens + message_tokens) as usize >= self.history_token_limit() {
-            self.compress_history(message_tokens as usize).await;
+        let tokens = (total_tokens + message_tokens) as usize;
+        if tokens >= self.history_token_limit() {
+            if self.auto_compress {
+                self.compress_history(message_tokens as usize).await?;
+            } else if tokens > self.history_token_limit() {
+                return Err(HistoryOverflowError { tokens, limit: self.history_token_limit() }.into());
+            }
         }
         
         self.messages.push(message);
+        Ok(())
     }
 
-    pub async fn add_message_0(&mut self, message: Message) {
+    pub async fn add_message_0(&mut self, message: Message) -> anyhow::Result<()> {
         self.add_message(message.message, message.sender).await
     }
 
+    // For bulk imports: disable, add everything, then call summarize_now once
+    pub fn set_auto_compress(&mut self, auto_compress: bool) {
+        self.auto_compress = auto_compress;
+    }
+
+    pub async fn summarize_now(&mut self) -> anyhow::Result<()> {
+        // Everything fits the history target, so there is nothing to summarize
+        if self.retained_count(0) == self.messages.len() {
+            return Ok(());
+        }
+
+        self.compress_history(0).await
+    }
+
+    // The user list as an aliases system message, sent after the leading system messages of every request. Users
+    // that don't fit in alias_budget are left out, newest first
+    pub fn alias_message(&self) -> Option<ChatMessage> {
+        if self.users.users.is_empty() {
+            return None;
+        }
+
+        let mut prompt = PROMPT_ALIASES.to_string();
+        for (index, aliases) in self.users.users.iter().enumerate() {
+            let extended = format!("{prompt}\n{}", format_alias_line(index, aliases, DEFAULT_UNKNOWN_USER_PLACEHOLDER));
+            if count_message_tokens(&get_alias_message(extended.clone()), &self.encoding, &self.model) as usize > self.alias_budget {
+                eprintln!("User aliases exceed the alias budget of {} tokens; listing {index} of {} users", self.alias_budget, self.users.users.len());
+                break;
+            }
+            prompt = extended;
+        }
+
+        return Some(get_alias_message(prompt));
+    }
+
+    pub fn alias_token_count(&self) -> usize {
+        if let Some(ref message) = self.alias_message() {
+            count_message_tokens(message, &self.encoding, &self.model) as usize
+        } else {
+            0
+        }
+    }
+
     pub async fn generate_response(&self) -> anyhow::Result<Option<Message>> {
         let mut history = self.chat_to_history(None);
+        if let Some(alias_message) = self.alias_message() {
+            let index = history.iter().take_while(|message| matches!(message.role, Role::System)).count();
+            history.insert(index, alias_message);
+        }
+
+        if !self.facts.is_empty() {
+            let index = history.iter().take_while(|message| matches!(message.role, Role::System)).count();
+            history.insert(index, get_facts_message(&self.facts));
+        }
+
         if let Some(ref summary) = self.summary {
-            history.insert(0, get_summary_message(Some(summary.clone())));
+            let index = match self.summary_placement {
+                SummaryPlacement::Front => 0,
+                SummaryPlacement::AfterSystem => history.iter().take_while(|message| matches!(message.role, Role::System)).count(),
+                SummaryPlacement::Custom(index) => min(index, history.len())
+            };
+            history.insert(index, get_summary_message(Some(summary.clone())));
         }
 
-        let response = self.openai_context.create_chat_completion_sync(
-            ChatHistoryBuilder::default()
-                .messages(history)
-                .max_tokens(self.history_token_limit() as u64)
-                .model(self.model.clone())
-        ).await?.choices.remove(0).message.content;
+        // The reply has to fit in whatever the assembled prompt leaves of the window
+        let prompt_tokens = history.iter()
+            .map(|message| count_message_tokens(message, &self.encoding, &self.model))
+            .sum::<i64>() as usize;
+        let tpm = get_tokens_per_message(&self.model) as usize;
+        let max_tokens = min(self.history_token_limit(), self.max_tokens.saturating_sub(prompt_tokens + REPLY_PRIMING_TOKENS + tpm));
+
+        let response = self.complete(&self.model, history, max_tokens).await?;
 
         Ok(if response.len() > 0 {
             Some(Message::new(User::Assistant, response))
@@ -285,11 +789,11 @@ impl Context {
         })
     }
 
-    pub fn get_user_aliases(&self, id: usize) -> Option<&mut UserAliases> {
+    pub fn get_user_aliases(&mut self, id: usize) -> Option<&mut UserAliases> {
         if id >= self.users.users.len() {
             None
         } else {
-            Some(&mut self.users.users[id])
+            Some(&mut self.users.as_mut().users_mut()[id])
         }
     }
 
@@ -298,12 +802,8 @@ impl Context {
 
         let mut history = Vec::new();
 
-        for (index, msg) in self.messages[self.messages.len() - last_n..self.messages.len()].iter().enumerate() {
-            history.push(msg.to_chat_message(if let User::User { aliases } = msg.sender {
-                Some(index)
-            } else {
-                None
-            }));
+        for msg in &self.messages[self.messages.len() - last_n..self.messages.len()] {
+            history.push(msg.to_chat_message(self.assistant_label()));
         }
 
         return history;
@@ -320,102 +820,541 @@ impl Context {
         return total;
     }
 
-    async fn compress_history(&mut self, new_tokens: usize) -> anyhow::Result<()> {
-        let mut permitted_history_size = if new_tokens > self.history_target {
-            0
-        } else {
-            self.history_target - new_tokens
-        };
+    // Compression compacts to history_target minus this, leaving headroom before the next compression
+    pub fn set_compression_hysteresis(&mut self, compression_hysteresis: usize) {
+        self.compression_hysteresis = compression_hysteresis;
+    }
+
+    // How many of the newest messages fit in the history target once new_tokens are added. Everything older is
+    // summarized
+    fn retained_count(&self, new_tokens: usize) -> usize {
+        let mut permitted_history_size = self.history_target
+            .saturating_sub(self.compression_hysteresis)
+            .saturating_sub(new_tokens);
 
-        let mut skip_count = 0;
+        let mut retained = 0;
 
-        for (index, message) in self.messages.iter().enumerate() {
-            let tokens = count_message_tokens(&message.to_chat_message(self.find_user(&message.sender)), &self.encoding, &self.model) as usize;
+        for message in self.messages.iter().rev() {
+            let tokens = count_message_tokens(&message.to_chat_message(self.assistant_label()), &self.encoding, &self.model) as usize;
             if tokens > permitted_history_size {
                 break;
             }
 
             permitted_history_size -= tokens;
-            skip_count = index + 1;
+            retained += 1;
+        }
+
+        return retained;
+    }
+
+    pub fn set_summary_instruction_placement(&mut self, summary_instruction_placement: SummaryInstructionPlacement) {
+        self.summary_instruction_placement = summary_instruction_placement;
+    }
+
+    pub fn set_summary_format(&mut self, summary_format: SummaryFormat) {
+        self.summary_format = summary_format;
+    }
+
+    pub fn facts(&self) -> &[(String, String)] {
+        &self.facts
+    }
+
+    // The summary as the model wrote it: prose followed by any facts as "- key: value" lines
+    pub fn summary_text(&self) -> Option<String> {
+        let mut lines = self.summary.iter().cloned().collect::<Vec<String>>();
+        lines.extend(self.facts.iter().map(|(key, value)| format!("- {key}: {value}")));
+        return if lines.is_empty() { None } else { Some(lines.join("\n")) };
+    }
+
+    // Summary text from before the most recent compression, for diffing against summary_text
+    pub fn previous_summary(&self) -> Option<&str> {
+        self.previous_summary.as_deref()
+    }
+
+    // In Facts format, "- key: value" lines become structured facts and anything else is kept as prose
+    fn store_summary(&mut self, summary: String) {
+        self.previous_summary = self.summary_text();
+        if let SummaryFormat::Prose = self.summary_format {
+            self.summary = Some(summary);
+            self.facts.clear();
+            return;
+        }
+
+        let mut facts = Vec::new();
+        let mut prose = Vec::new();
+        for line in summary.lines() {
+            let fact = line.trim().strip_prefix("- ").and_then(|fact| fact.split_once(':'));
+            match fact {
+                Some((key, value)) => facts.push((key.trim().to_string(), value.trim().to_string())),
+                None => if !line.trim().is_empty() {
+                    prose.push(line.trim());
+                }
+            }
+        }
+
+        self.summary = if prose.is_empty() { None } else { Some(prose.join("\n")) };
+        self.facts = facts;
+    }
+
+    // Placement doesn't affect budgeting: the instruction costs the same wherever it goes
+    fn place_summary_instruction(&self, history: &mut Vec<ChatMessage>) {
+        match self.summary_instruction_placement {
+            SummaryInstructionPlacement::Before => history.insert(0, get_summary_instruction(self.summary_format)),
+            SummaryInstructionPlacement::After => history.push(get_summary_instruction(self.summary_format))
+        }
+    }
+
+    // The oldest messages are dropped into the summary; the newest ones are retained
+    pub fn plan_compression(&self, new_tokens: usize) -> CompressionPlan {
+        let retained = self.retained_count(new_tokens);
+        CompressionPlan {
+            dropped_indices: (0..self.messages.len() - retained).collect(),
+            retained
+        }
+    }
+
+    pub fn retained_after_compression(&self, incoming_tokens: usize) -> usize {
+        self.retained_count(incoming_tokens)
+    }
+
+    async fn compress_history(&mut self, new_tokens: usize) -> anyhow::Result<()> {
+        let compressed_count = self.messages.len() - self.retained_count(new_tokens);
+        if compressed_count == 0 {
+            return Ok(());
+        }
+        let history = self.summarization_request(compressed_count);
+
+        // Nothing is mutated until the summary has been received, so a failed call leaves messages and summary intact
+        let summary = self.complete(&self.model, history, self.summary_budget).await?;
+        self.store_summary(summary);
+
+        let drained = self.messages.drain(..compressed_count).collect::<Vec<Message>>();
+        if self.archive_compressed {
+            self.archive.extend(drained);
+        }
+
+        Ok(())
+    }
+
+    fn summarization_request(&self, compressed_count: usize) -> Vec<ChatMessage> {
+        let mut history = self.messages[..compressed_count].iter()
+            .map(|message| message.to_chat_message(self.assistant_label()))
+            .collect::<Vec<ChatMessage>>();
+        self.place_summary_instruction(&mut history);
+        return history;
+    }
+
+    // Prompt size of the request compress_history would send when summarizing the oldest compressed_count messages
+    pub fn summarization_request_tokens(&self, compressed_count: usize) -> usize {
+        self.summarization_request(min(compressed_count, self.messages.len())).iter()
+            .map(|message| count_message_tokens(message, &self.encoding, &self.model) as usize)
+            .sum()
+    }
+
+    // Re-summarizes only the current summary, e.g. after lowering the summary budget. Cheaper than rebuild_summary
+    pub async fn shrink_summary(&mut self, target_tokens: usize) -> anyhow::Result<()> {
+        let summary = match self.summary_text() {
+            Some(summary) => summary,
+            None => return Ok(())
+        };
+
+        let mut instruction = format!("{PROMPT_SHRINK_SUMMARY} {target_tokens} tokens.");
+        if let SummaryFormat::Facts = self.summary_format {
+            instruction.push_str(" Keep facts formatted as \"- key: value\", one per line.");
         }
 
-        let mut history = self.chat_to_history(Some(self.messages.len() - skip_count));
-        history.push(get_summary_instruction());
-        self.summary = Some(self.openai_context.create_chat_completion_sync(
-            ChatHistoryBuilder::default()
-                .max_tokens(self.summary_budget as u64)
-                .model(self.model.clone())
-                .messages(history)
-        ).await?.choices.remove(0).message.content);
+        let request = vec![
+            get_summary_message(Some(summary)),
+            ChatMessage::new(Role::System, instruction, None)
+        ];
+        let summary = self.complete(&self.model, request, target_tokens).await?;
+        self.store_summary(summary);
 
-        self.messages.drain(skip_count..);
+        Ok(())
+    }
+
+    pub fn set_archive_compressed(&mut self, archive_compressed: bool) {
+        self.archive_compressed = archive_compressed;
+    }
+
+    pub fn archived_messages(&self) -> &[Message] {
+        &self.archive
+    }
+
+    pub async fn rebuild_summary(&mut self) -> anyhow::Result<()> {
+        if !self.archive_compressed {
+            return Err(ArchiveDisabledError.into());
+        }
+
+        // Nothing to rebuild from. A restored summary, or one made before archiving was enabled, is kept
+        if self.archive.is_empty() {
+            return Ok(());
+        }
+
+        let mut history = self.archive_to_history();
+        self.place_summary_instruction(&mut history);
+
+        let summary = self.complete(&self.model, history, self.summary_budget).await?;
+        self.store_summary(summary);
 
         Ok(())
     }
+
+    // Summarizes the archive with another model and prompt for comparison. The current summary is left untouched
+    pub async fn resummarize_with(&mut self, model: &str, prompt: &str) -> anyhow::Result<String> {
+        if !self.archive_compressed {
+            return Err(ArchiveDisabledError.into());
+        }
+
+        let mut history = self.archive_to_history();
+        let instruction = ChatMessage::new(Role::System, prompt, None);
+        match self.summary_instruction_placement {
+            SummaryInstructionPlacement::Before => history.insert(0, instruction),
+            SummaryInstructionPlacement::After => history.push(instruction)
+        }
+
+        let summary = self.complete(model, history, self.summary_budget).await?;
+
+        Ok(summary)
+    }
+
+    fn archive_to_history(&self) -> Vec<ChatMessage> {
+        let mut history = Vec::new();
+        for message in &self.archive {
+            history.push(message.to_chat_message(self.assistant_label()));
+        }
+        return history;
+    }
+}
+
+impl Conversation for Context {
+    type Message = Message;
+
+    async fn add(&mut self, message: Message) -> anyhow::Result<()> {
+        self.add_message_0(message).await
+    }
+
+    async fn respond(&mut self) -> anyhow::Result<Option<Message>> {
+        let response = self.generate_response().await?;
+        if let Some(ref response) = response {
+            self.add_message_0(response.clone()).await?;
+        }
+        Ok(response)
+    }
+
+    // Everything generate_response sends: history plus the alias, facts and summary messages, and the reply priming
+    fn tokens(&self) -> usize {
+        let summary_tokens = if let Some(ref summary) = self.summary {
+            count_message_tokens(&get_summary_message(Some(summary.clone())), &self.encoding, &self.model)
+        } else {
+            0
+        };
+        let facts_tokens = if self.facts.is_empty() {
+            0
+        } else {
+            count_message_tokens(&get_facts_message(&self.facts), &self.encoding, &self.model)
+        };
+        self.count_message_tokens() as usize + summary_tokens as usize + facts_tokens as usize + self.alias_token_count() + REPLY_PRIMING_TOKENS
+    }
+}
+
+// Line-level diff between two summaries, based on their longest common subsequence of lines
+pub fn summary_diff(old: &str, new: &str) -> Vec<DiffLine> {
+    let old = old.lines().collect::<Vec<&str>>();
+    let new = new.lines().collect::<Vec<&str>>();
+
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            diff.push(DiffLine::Kept(old[i].to_string()));
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            diff.push(DiffLine::Removed(old[i].to_string()));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(new[j].to_string()));
+            j += 1;
+        }
+    }
+    diff.extend(old[i..].iter().map(|line| DiffLine::Removed(line.to_string())));
+    diff.extend(new[j..].iter().map(|line| DiffLine::Added(line.to_string())));
+
+    return diff;
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+// The summary budget including its message overhead, and the budget for the summary instruction. Reserves for the
+// longer of the two instructions so the format can be switched later
+fn summary_budgets(summary_budget: usize, encoding: &CoreBPE, model: &str) -> (usize, usize) {
+    let summary_instruction_budget = count_message_tokens(&get_summary_instruction(SummaryFormat::Prose), encoding, model)
+        .max(count_message_tokens(&get_summary_instruction(SummaryFormat::Facts), encoding, model)) as usize;
+    return (summary_budget + count_message_tokens(&get_summary_message(None), encoding, model) as usize, summary_instruction_budget);
+}
+
+fn check_budgets(max_tokens: usize, summary_budget: usize, summary_instruction_budget: usize, history_target: usize, alias_budget: usize, response_reserve: usize) -> Result<(), ContextOverrunError> {
+    if history_target + summary_budget + alias_budget + summary_instruction_budget + response_reserve >= max_tokens {
+        Err(ContextOverrunError::new(max_tokens, summary_budget, history_target, alias_budget, response_reserve))
+    } else {
+        Ok(())
+    }
 }
 
-fn get_summary_instruction() -> ChatMessage {
-    ChatMessage::new(Role::System, PROMPT_COMPRESS, None)
+fn get_summary_instruction(format: SummaryFormat) -> ChatMessage {
+    ChatMessage::new(Role::System, match format {
+        SummaryFormat::Prose => PROMPT_COMPRESS,
+        SummaryFormat::Facts => PROMPT_COMPRESS_FACTS
+    }, None)
+}
+fn get_facts_message(facts: &[(String, String)]) -> ChatMessage {
+    let facts = facts.iter().map(|(key, value)| format!("- {key}: {value}")).collect::<Vec<String>>().join("\n");
+    ChatMessage::new(Role::System, format!("Known facts:\n{facts}"), Some("Facts".to_string()))
+}
+fn get_alias_message(prompt: String) -> ChatMessage {
+    ChatMessage::new(Role::System, prompt, Some("aliases".to_string()))
 }
 fn get_summary_message(summary: Option<String>) -> ChatMessage {
     ChatMessage::new(Role::System, if let Some(ref message) = summary { message } else { "" }, Some("Context".to_string()))
 }
 
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+    use crate::model::test_encoding;
 
-fn get_tokens_per_message(model: &str) -> Option<i64> {
-    match model {
-        "gpt-4" | "gpt-4-32k" => Some(3),
-        "gpt-3.5-turbo" => Some(4),
-        _ => None
+    async fn test_context(max_tokens: usize) -> Context {
+        let encoding = test_encoding();
+        let budget = NonZeroUsize::new(64).unwrap();
+        Context::new(NonZeroUsize::new(max_tokens).unwrap(), "gpt-4".to_string(), encoding, OpenAIContext::new(String::new()), budget, budget, budget, 0).unwrap()
     }
-}
 
-fn get_tokens_per_name(model: &str) -> Option<i64> {
-    match model {
-        "gpt-4" | "gpt-4-32k" => Some(1),
-        "gpt-3.5-turbo" => Some(-1),
-        _ => None
+    fn test_chat_context() -> ChatContext {
+        ChatContext::from_parts(ContextParts {
+            model: "gpt-4".to_string(),
+            encoding: test_encoding(),
+            max_tokens: 8192,
+            api_context: OpenAIContext::new(String::new()),
+            history: Vec::new(),
+            user_aliases: Vec::new(),
+            summary: None,
+            assistant_name: None
+        })
     }
-}
 
-fn role_str(role: &Role) -> &str {
-    match role {
-        Role::Assistant => "Assistant",
-        Role::System => "System",
-        Role::User => "User",
+    #[tokio::test]
+    async fn failed_compression_keeps_messages_and_summary() {
+        let mut context = test_context(400).await.with_initial_summary("Earlier conversation".to_string()).unwrap();
+        context.set_completion_backend(Box::new(|_, _| Err(anyhow::anyhow!("backend unavailable"))));
+
+        let mut added = 0;
+        let mut failure = None;
+        for _ in 0..50 {
+            match context.add_message("word ".repeat(40), User::System).await {
+                Ok(()) => added += 1,
+                Err(err) => {
+                    failure = Some(err);
+                    break;
+                }
+            }
+        }
+
+        assert!(failure.is_some());
+        assert_eq!(context.messages.len(), added);
+        assert_eq!(context.summary.as_deref(), Some("Earlier conversation"));
+        assert!(context.archive.is_empty());
     }
-}
 
-fn count_message_tokens(message: &ChatMessage, encoding: &CoreBPE, model: &str) -> i64 {
-    let tpm = get_tokens_per_message(model).expect("Unknown tokens-per-message value");
-    let tpn = get_tokens_per_name(model).expect("Unknown tokens-per-name value");
+    #[tokio::test]
+    async fn compression_summarizes_the_oldest_messages() {
+        let mut context = test_context(400).await;
+        let summarized = Rc::new(Cell::new(Vec::new()));
+        let seen = summarized.clone();
+        context.set_completion_backend(Box::new(move |messages, _| {
+            let mut contents = seen.take();
+            contents.extend(messages.iter().filter(|message| message.name.is_none()).map(|message| message.content.clone()));
+            seen.set(contents);
+            Ok(ChatMessage::new(Role::Assistant, "A group chat", None))
+        }));
+
+        for index in 0..20 {
+            context.add_message(format!("{index} {}", "word ".repeat(20)), User::System).await.unwrap();
+        }
 
-    return tpm + encoding.encode_ordinary(&message.content).len() as i64 + encoding.encode_ordinary(role_str(&message.role)).len() as i64 + if let Some(ref name) = message.name {
-        tpn + encoding.encode_ordinary(name).len() as i64
-    } else { 0i64 };
-}
+        let summarized = summarized.take();
+        assert!(summarized.iter().any(|content| content.starts_with("0 ")));
+        assert!(!summarized.iter().any(|content| content.starts_with("19 ")));
+        assert!(context.messages.last().unwrap().message.starts_with("19 "));
+        assert!(!context.messages[0].message.starts_with("0 "));
+    }
 
-async fn get_model(model: &str) -> Option<CoreBPE> {
-    return match model {
-        "gpt-4" | "gpt-4-32k" | "gpt-3.5-turbo" | "text-embedding-ada-002" => {
-            let model = model_cl100k_base().await;
-            assert!(model.is_ok(), "Could not download model (model_cl100k_base): {:?}", model);
+    #[tokio::test]
+    async fn reply_cap_shrinks_when_prompt_nearly_fills_window() {
+        let messages = (0..7).map(|_| Message::new(User::System, "word ".repeat(40))).collect();
+        let mut context = test_context(600).await.with_initial_messages(messages).unwrap();
+
+        let requested = Rc::new(Cell::new(0));
+        let seen = requested.clone();
+        context.set_completion_backend(Box::new(move |_, max_tokens| {
+            seen.set(max_tokens);
+            Ok(ChatMessage::new(Role::Assistant, "ok", None))
+        }));
+        context.generate_response().await.unwrap();
+
+        let tpm = get_tokens_per_message("gpt-4") as usize;
+        assert!(requested.get() < context.history_token_limit());
+        assert_eq!(requested.get(), 600 - context.tokens() - tpm);
+    }
+
+    #[tokio::test]
+    async fn requests_include_aliases_within_budget() {
+        let mut context = test_context(600).await;
+        context.users = Box::pin(UserList { users: (0..40).map(|index| vec![format!("User number {index}")]).collect(), _pin: PhantomPinned });
+        let sender = User::User { aliases: NonNull::from(&context.users.users[0]) };
+        context.add_message("Hello".to_string(), sender).await.unwrap();
+
+        let sent = Rc::new(Cell::new(None));
+        let seen = sent.clone();
+        context.set_completion_backend(Box::new(move |messages, _| {
+            seen.set(messages.iter().find(|message| message.name.as_deref() == Some("aliases")).cloned());
+            Ok(ChatMessage::new(Role::Assistant, "ok", None))
+        }));
+        context.generate_response().await.unwrap();
+
+        let aliases = sent.take().unwrap();
+        assert!(aliases.content.starts_with(PROMPT_ALIASES));
+        assert!(aliases.content.contains("u0: \"User number 0\""));
+        assert!(count_message_tokens(&aliases, &context.encoding, &context.model) as usize <= context.alias_budget);
+        assert_eq!(context.alias_token_count(), count_message_tokens(&aliases, &context.encoding, &context.model) as usize);
+    }
 
-            let model = cl100k_base(model.unwrap());
-            assert!(model.is_ok(), "Could not load model (cl100k_base): {:?}", model.err().unwrap());
-            
-            return Some(model.unwrap());
+    #[tokio::test]
+    async fn assistant_messages_count_the_same_in_both_contexts() {
+        for policy in [AssistantNamePolicy::Omit, AssistantNamePolicy::Persona] {
+            let mut chat_context = test_chat_context();
+            chat_context.set_assistant_name("Jarvis".to_string());
+            chat_context.set_assistant_name_policy(policy);
+            // Whatever name the API returns is replaced according to the policy
+            chat_context.set_completion_backend(Box::new(|_, _| Ok(ChatMessage::new(Role::Assistant, "Hello there", Some("assistant".to_string())))));
+            let reply = chat_context.respond().await.unwrap().unwrap();
+
+            let mut context = test_context(600).await;
+            context.set_assistant_name("Jarvis".to_string());
+            context.set_assistant_name_policy(policy);
+            let message = Message::new(User::Assistant, "Hello there".to_string()).to_chat_message(context.assistant_label());
+
+            assert_eq!(
+                count_message_tokens(&reply.chat_message, &context.encoding, &context.model),
+                count_message_tokens(&message, &context.encoding, &context.model)
+            );
         }
-        _ => None
     }
-}
 
-fn get_max_tokens(model: &str) -> Option<i64> {
-    match model {
-        "gpt-4" => Some(8192),
-        "gpt-4-32k" => Some(32768),
-        "gpt-3.5-turbo" => Some(4096),
-        "code-davinci-002" => Some(8001),
-        _ => None
+    #[tokio::test]
+    async fn prompts_count_the_same_in_both_contexts() {
+        let mut chat_context = test_chat_context();
+        chat_context.push_chat_message(ChatMessage::new(Role::Assistant, "Hello there", None), MessageType::AssistantMessage);
+
+        let context = test_context(600).await.with_initial_messages(vec![Message::new(User::Assistant, "Hello there".to_string())]).unwrap();
+
+        assert_eq!(chat_context.tokens(), context.tokens());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn summarize_now_skips_history_that_fits() {
+        let mut context = test_context(600).await.with_initial_messages(vec![Message::new(User::System, "Hello".to_string())]).unwrap();
+        context.set_completion_backend(Box::new(|_, _| panic!("nothing should be summarized")));
+
+        context.summarize_now().await.unwrap();
+        assert_eq!(context.messages.len(), 1);
+        assert!(context.summary.is_none());
+    }
+
+    #[tokio::test]
+    async fn converted_users_come_only_from_real_ids() {
+        let mut chat_context = test_chat_context();
+        for (id, name) in [(3, "Anna"), (0, "James")] {
+            let sender = UserAlias::new(id, vec![name.to_string()]);
+            chat_context.push_message(MetaChatMessage::new(ChatMessage::new(Role::User, "Hello", Some(name.to_string())), MessageType::UserMessage { sender }));
+        }
+
+        let budget = NonZeroUsize::new(64).unwrap();
+        let context = Context::from_chat_context(chat_context, budget, budget, budget, 0).ok().unwrap();
+
+        assert_eq!(context.users.users, [vec!["James".to_string()], vec!["Anna".to_string()]]);
+        assert_eq!(context.messages[0].user_index, Some(1));
+        assert_eq!(context.messages[1].user_index, Some(0));
+        assert!(!context.alias_message().unwrap().content.contains(DEFAULT_UNKNOWN_USER_PLACEHOLDER));
+    }
+
+    #[tokio::test]
+    async fn converted_senders_with_equal_names_stay_apart() {
+        let mut chat_context = test_chat_context();
+        for (id, names) in [(0, vec![]), (1, vec![]), (2, vec!["Anna".to_string()]), (3, vec!["Anna".to_string()])] {
+            let sender = UserAlias::new(id, names);
+            chat_context.push_message(MetaChatMessage::new(ChatMessage::new(Role::User, "Hello", None), MessageType::UserMessage { sender }));
+        }
+
+        let budget = NonZeroUsize::new(64).unwrap();
+        let context = Context::from_chat_context(chat_context, budget, budget, budget, 0).ok().unwrap();
+
+        assert_eq!(context.users.users.len(), 4);
+        let indices = context.messages.iter().map(|message| message.user_index).collect::<Vec<Option<usize>>>();
+        assert_eq!(indices, [Some(0), Some(1), Some(2), Some(3)]);
+    }
+
+    #[tokio::test]
+    async fn failed_conversion_returns_the_chat_context() {
+        let mut chat_context = test_chat_context();
+        chat_context.push_message(MetaChatMessage::new(ChatMessage::new(Role::User, "Hello", None), MessageType::AssistantMessage));
+        chat_context.add_few_shot("Hello".to_string(), "Hi".to_string());
+
+        let budget = NonZeroUsize::new(64).unwrap();
+        let (mut chat_context, _) = Context::from_chat_context(chat_context, budget, budget, budget, 0).err().unwrap();
+        assert_eq!(chat_context.get_history().len(), 1);
+
+        chat_context.clear_few_shots();
+        chat_context.set_system_message(SystemSlot::Context, "word ".repeat(200));
+        let (chat_context, _) = Context::from_chat_context(chat_context, budget, budget, budget, 0).err().unwrap();
+        assert_eq!(chat_context.get_history().len(), 1);
+        assert!(chat_context.get_system_message(SystemSlot::Context).is_some());
+    }
+
+    #[tokio::test]
+    async fn labels_survive_compression() {
+        let mut context = test_context(400).await;
+        context.users = Box::pin(UserList { users: vec![vec!["James".to_string()], vec!["Anna".to_string()]], _pin: PhantomPinned });
+        context.set_completion_backend(Box::new(|_, _| Ok(ChatMessage::new(Role::Assistant, "A group chat", None))));
+
+        for index in 0..20 {
+            let sender = User::User { aliases: NonNull::from(&context.users.users[index % 2]) };
+            context.add_message(format!("{index} {}", "word ".repeat(20)), sender).await.unwrap();
+
+            for message in context.chat_to_history(None) {
+                let sent_by = message.content.split(' ').next().unwrap().parse::<usize>().unwrap() % 2;
+                assert_eq!(message.name, Some(format!("u{sent_by}")));
+            }
+        }
+
+        assert!(context.summary.is_some());
+        assert!(!context.messages[0].message.starts_with("0 "));
+        assert_eq!(context.user_count(), 2);
+    }
+}
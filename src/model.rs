@@ -0,0 +1,168 @@
+use std::{collections::HashSet, sync::{Mutex, OnceLock}};
+
+use openai_rs::chat::{ChatMessage, Role};
+use tiktoken::{CoreBPE, model::{model_cl100k_base, cl100k_base}};
+
+// Context window per chat model. Every model listed here must also have an encoding and message overheads below
+const MODEL_MAX_TOKENS: &[(&str, i64)] = &[
+    ("gpt-4", 8192),
+    ("gpt-4-32k", 32768),
+    ("gpt-3.5-turbo", 4096),
+];
+
+// USD per 1K prompt and completion tokens
+const MODEL_PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-4", 0.03, 0.06),
+    ("gpt-4-32k", 0.06, 0.12),
+    ("gpt-3.5-turbo", 0.0015, 0.002),
+];
+
+// Chat models accepted by ChatContext::new and message::Context::new_from_api (fine-tuned variants of these are accepted too)
+pub fn supported_models() -> &'static [&'static str] {
+    static MODELS: OnceLock<Vec<&'static str>> = OnceLock::new();
+    return MODELS.get_or_init(|| MODEL_MAX_TOKENS.iter().map(|(model, _)| *model).collect());
+}
+
+// Fine-tuned models are named like "ft:gpt-3.5-turbo-0613:org::abc123" and snapshots like "gpt-4-0613";
+// table lookups use the base model
+pub(crate) fn base_model(model: &str) -> &str {
+    let model = match model.strip_prefix("ft:") {
+        Some(rest) => rest.split(':').next().unwrap_or(rest),
+        None => model
+    };
+
+    return match model.rsplit_once('-') {
+        Some((base, date)) if date.len() == 4 && date.chars().all(|c| c.is_ascii_digit()) => base,
+        _ => model
+    };
+}
+
+pub(crate) async fn get_model(model: &str) -> Option<CoreBPE> {
+    return match base_model(model) {
+        "gpt-4" | "gpt-4-32k" | "gpt-3.5-turbo" | "text-embedding-ada-002" => get_encoding("cl100k_base").await,
+        _ => None
+    }
+}
+
+// Only cl100k_base ships with the bundled tiktoken
+pub(crate) async fn get_encoding(name: &str) -> Option<CoreBPE> {
+    return match name {
+        "cl100k_base" => {
+            let model = match model_cl100k_base().await {
+                Ok(model) => model,
+                Err(err) => {
+                    eprintln!("Could not download model (model_cl100k_base): {:?}", err);
+                    return None;
+                }
+            };
+
+            match cl100k_base(model) {
+                Ok(model) => Some(model),
+                Err(err) => {
+                    eprintln!("Could not load model (cl100k_base): {:?}", err);
+                    None
+                }
+            }
+        }
+        _ => None
+    }
+}
+
+pub(crate) fn get_max_tokens(model: &str) -> Option<i64> {
+    let model = base_model(model);
+    return MODEL_MAX_TOKENS.iter().find(|(name, _)| *name == model).map(|(_, tokens)| *tokens);
+}
+
+pub(crate) fn get_prices(model: &str) -> Option<(f64, f64)> {
+    let model = base_model(model);
+    return MODEL_PRICES.iter().find(|(name, _, _)| *name == model).map(|(_, prompt, completion)| (*prompt, *completion));
+}
+
+// Models missing from the overhead tables are counted like gpt-4 rather than panicking mid-count. Every model in
+// MODEL_MAX_TOKENS has overheads (code-davinci-002 had none and was removed), so this covers models passed to
+// message::Context or resummarize_with directly
+const DEFAULT_TOKENS_PER_MESSAGE: i64 = 3;
+const DEFAULT_TOKENS_PER_NAME: i64 = 1;
+
+pub(crate) fn get_tokens_per_message(model: &str) -> i64 {
+    match base_model(model) {
+        "gpt-4" | "gpt-4-32k" => 3,
+        "gpt-3.5-turbo" => 4,
+        _ => {
+            warn_unknown_overhead(model);
+            DEFAULT_TOKENS_PER_MESSAGE
+        }
+    }
+}
+
+pub(crate) fn get_tokens_per_name(model: &str) -> i64 {
+    match base_model(model) {
+        "gpt-4" | "gpt-4-32k" => 1,
+        "gpt-3.5-turbo" => -1,
+        _ => {
+            warn_unknown_overhead(model);
+            DEFAULT_TOKENS_PER_NAME
+        }
+    }
+}
+
+// Token counting runs on every message, so each model is only warned about once
+fn warn_unknown_overhead(model: &str) {
+    static WARNED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    let mut warned = WARNED.get_or_init(|| Mutex::new(HashSet::new())).lock().unwrap();
+    if warned.insert(model.to_string()) {
+        eprintln!("Unknown message token overhead for model {model}; assuming {DEFAULT_TOKENS_PER_MESSAGE} per message and {DEFAULT_TOKENS_PER_NAME} per name");
+    }
+}
+
+pub(crate) fn role_str(role: &Role) -> &str {
+    match role {
+        Role::Assistant => "Assistant",
+        Role::System => "System",
+        Role::User => "User",
+    }
+}
+
+pub(crate) fn count_message_tokens(message: &ChatMessage, encoding: &CoreBPE, model: &str) -> i64 {
+    let tpm = get_tokens_per_message(model);
+    let tpn = get_tokens_per_name(model);
+
+    return tpm + encoding.encode_ordinary(&message.content).len() as i64 + encoding.encode_ordinary(role_str(&message.role)).len() as i64 + if let Some(ref name) = message.name {
+        tpn + encoding.encode_ordinary(name).len() as i64
+    } else { 0i64 };
+}
+
+// Offline stand-in for cl100k_base: every byte plus a few whole words, so tests don't need to download an encoding
+#[cfg(test)]
+pub(crate) fn test_encoding() -> CoreBPE {
+    const WORDS: &[&str] = &["User", "System", "Assistant", "word", "Hello", "Hi", "there", "ok", "group", "chat", "Earlier", "conversation", "Anna", "James", "Jarvis", "Always", "use", "the", "first", "listed", "name", "when", "referring", "to", "number"];
+
+    let words = WORDS.iter().flat_map(|word| [word.to_string(), format!(" {word}")]).map(String::into_bytes);
+    let vocabulary = (0..=255u8).map(|byte| vec![byte]).chain(words);
+    return CoreBPE::new(vocabulary.enumerate().map(|(rank, bytes)| (bytes, rank)).collect(), Default::default(), r"\s?\S+|\s+").unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base_model_strips_fine_tune_and_snapshot_names() {
+        assert_eq!(base_model("gpt-4"), "gpt-4");
+        assert_eq!(base_model("gpt-4-0613"), "gpt-4");
+        assert_eq!(base_model("gpt-4-32k-0613"), "gpt-4-32k");
+        assert_eq!(base_model("ft:gpt-3.5-turbo:org::abc123"), "gpt-3.5-turbo");
+        assert_eq!(base_model("ft:gpt-3.5-turbo-0613:org::abc123"), "gpt-3.5-turbo");
+        assert_eq!(base_model("ft:gpt-3.5-turbo-0613:my-org:custom-suffix:7p4lURel"), "gpt-3.5-turbo");
+        assert_eq!(base_model("ft:gpt-4-0613:org::abc123"), "gpt-4");
+        assert_eq!(base_model("text-embedding-ada-002"), "text-embedding-ada-002");
+    }
+
+    #[test]
+    fn fine_tuned_models_use_base_model_tables() {
+        assert_eq!(get_max_tokens("ft:gpt-3.5-turbo-0613:org::abc123"), Some(4096));
+        assert_eq!(get_max_tokens("ft:gpt-4-0613:org::abc123"), Some(8192));
+        assert_eq!(get_tokens_per_message("ft:gpt-3.5-turbo-0613:org::abc123"), 4);
+        assert_eq!(get_max_tokens("ft:davinci-002:org::abc123"), None);
+    }
+}
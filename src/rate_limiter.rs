@@ -0,0 +1,96 @@
+use std::{num::NonZeroU32, time::Duration};
+
+use tokio::{sync::Mutex, time::Instant};
+
+struct Buckets {
+    requests: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+pub struct RateLimiter {
+    requests_per_minute: f64,
+    tokens_per_minute: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: NonZeroU32, tokens_per_minute: NonZeroU32) -> Self {
+        let requests_per_minute = requests_per_minute.get();
+        let tokens_per_minute = tokens_per_minute.get();
+        Self {
+            requests_per_minute: requests_per_minute as f64,
+            tokens_per_minute: tokens_per_minute as f64,
+            buckets: Mutex::new(Buckets {
+                requests: requests_per_minute as f64,
+                tokens: tokens_per_minute as f64,
+                last_refill: Instant::now()
+            })
+        }
+    }
+
+    // Waits until both buckets can cover one request of the given token cost, then consumes it
+    pub async fn acquire(&self, tokens: usize) {
+        // A request larger than the whole bucket would never fit, so it only waits for a full bucket
+        let tokens = (tokens as f64).min(self.tokens_per_minute);
+
+        loop {
+            let wait = {
+                let mut buckets = self.buckets.lock().await;
+                self.refill(&mut buckets);
+
+                if buckets.requests >= 1.0 && buckets.tokens >= tokens {
+                    buckets.requests -= 1.0;
+                    buckets.tokens -= tokens;
+                    return;
+                }
+
+                let request_wait = (1.0 - buckets.requests).max(0.0) / self.requests_per_minute * 60.0;
+                let token_wait = (tokens - buckets.tokens).max(0.0) / self.tokens_per_minute * 60.0;
+                Duration::from_secs_f64(request_wait.max(token_wait))
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn refill(&self, buckets: &mut Buckets) {
+        let now = Instant::now();
+        let elapsed_minutes = now.duration_since(buckets.last_refill).as_secs_f64() / 60.0;
+        buckets.requests = (buckets.requests + elapsed_minutes * self.requests_per_minute).min(self.requests_per_minute);
+        buckets.tokens = (buckets.tokens + elapsed_minutes * self.tokens_per_minute).min(self.tokens_per_minute);
+        buckets.last_refill = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spaces_requests_once_the_bucket_is_empty() {
+        tokio::time::pause();
+        let rate_limiter = RateLimiter::new(NonZeroU32::new(1).unwrap(), NonZeroU32::new(1000).unwrap());
+        let start = Instant::now();
+
+        rate_limiter.acquire(10).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        rate_limiter.acquire(10).await;
+        assert!(start.elapsed() >= Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn waits_for_enough_tokens() {
+        tokio::time::pause();
+        let rate_limiter = RateLimiter::new(NonZeroU32::new(1000).unwrap(), NonZeroU32::new(100).unwrap());
+        let start = Instant::now();
+
+        rate_limiter.acquire(100).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        rate_limiter.acquire(50).await;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(30) && elapsed < Duration::from_secs(31));
+    }
+}
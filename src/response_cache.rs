@@ -0,0 +1,54 @@
+use std::collections::{HashMap, VecDeque};
+
+pub struct ResponseCache<V: Clone> {
+    capacity: usize,
+    entries: HashMap<u64, V>,
+    // Least recently used key at the front
+    order: VecDeque<u64>,
+}
+
+impl<V: Clone> ResponseCache<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new()
+        }
+    }
+
+    pub fn get(&mut self, key: u64) -> Option<V> {
+        let value = self.entries.get(&key)?.clone();
+        self.touch(key);
+        Some(value)
+    }
+
+    pub fn insert(&mut self, key: u64, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.insert(key, value).is_some() {
+            self.touch(key);
+            return;
+        }
+
+        self.order.push_back(key);
+        if self.order.len() > self.capacity {
+            if let Some(evicted) = self.order.pop_front() {
+                self.entries.remove(&evicted);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn touch(&mut self, key: u64) {
+        if let Some(index) = self.order.iter().position(|entry| *entry == key) {
+            self.order.remove(index);
+        }
+        self.order.push_back(key);
+    }
+}